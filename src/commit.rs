@@ -1,5 +1,5 @@
 /// A struct that holds the things that we care about about a commit.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct Commit<'a> {
     /// We don't care what comes before author/committer. Includes the newline.
     pub preamble: &'a str,
@@ -7,16 +7,34 @@ pub struct Commit<'a> {
     pub author: &'a str,
     /// The author timestamp. This is what we will twiddle to create new commit hashes.
     pub author_timestamp: i64,
-    /// The textual timezone (we don't care about this really)
-    pub author_timezone: &'a str,
+    /// The author's UTC offset, e.g. `-0500`. `force_prefix` can twiddle this too, so it's owned
+    /// rather than borrowed from the original commit text.
+    pub author_timezone: String,
     /// The committer's name and email
     pub committer: &'a str,
     /// The committer timestamp. This is what we will twiddle to create new commit hashes.
     pub committer_timestamp: i64,
-    /// The textual timezone (we don't care about this really)
-    pub committer_timezone: &'a str,
-    /// The commit message itself. This typically includes the trailing newline.
-    pub message: &'a str,
+    /// The committer's UTC offset, e.g. `-0500`. `force_prefix` can twiddle this too, so it's
+    /// owned rather than borrowed from the original commit text.
+    pub committer_timezone: String,
+    /// Any header lines (and their folded continuation lines) that appear after `committer`,
+    /// e.g. `gpgsig`, `encoding`, or `mergetag`, in the order they appeared.
+    pub extra_headers: Vec<ExtraHeader<'a>>,
+    /// The commit message itself, typically including the trailing newline. Owned rather than
+    /// borrowed, since the nonce-trailer search mode in `force_prefix_nonce` appends to it.
+    pub message: String,
+}
+
+/// A single header line that appears after `committer`, together with any folded continuation
+/// lines (lines beginning with a single space, as git uses for multi-line headers like
+/// `gpgsig`) that belong to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtraHeader<'a> {
+    /// The header name, e.g. `gpgsig` or `encoding`.
+    pub name: &'a str,
+    /// The header, byte-for-byte as it appeared, including its folded continuation lines and
+    /// their newlines.
+    pub raw: &'a str,
 }
 
 /// Empty struct that represents that we failed to parse the commit.
@@ -26,17 +44,21 @@ pub struct CommitError;
 impl<'a> Commit<'a> {
     /// Parse a string into a commit object.
     pub fn parse(commit: &'a str) -> Result<Commit<'a>, CommitError> {
-        let mut i = commit.splitn(2, "\n\n");
-        let header = i.next().ok_or(CommitError)?;
-        let message = i.next().ok_or(CommitError)?;
+        // Split the commit into its header and its message at the first blank line. We keep the
+        // newline that ends the header's last line on the header side, so every header line
+        // (including the last one) is newline-terminated the same way, whether that's the
+        // committer line or a trailing gpgsig/encoding/mergetag header.
+        let boundary = commit.find("\n\n").ok_or(CommitError)?;
+        let header = &commit[..boundary + 1];
+        let message = &commit[boundary + 2..];
 
-        // Split the header into 3 parts: the preamble, the author line, and the committer line.
+        // Split the header into 3 parts: the preamble, the author line, and everything after it.
         // The preamble can be "tree {hash}\nparent {hash}" or just "tree {hash}". Either way, we
         // just want everything up to the "author" line.
         let author_line_start_idx = header.find("author").ok_or(CommitError)?;
         let (preamble, rest) = header.split_at(author_line_start_idx);
-        let author_line_end_idx = rest.find("\n").ok_or(CommitError)?;
-        let (author_line, committer_line) = rest.split_at(author_line_end_idx);
+        let (author_line, rest) = split_line(rest);
+        let (committer_line, rest) = split_line(rest);
 
         // Split "author Some Name <example@wherever.com> 1524680608 -0500" into three parts:
         // * "-0500"
@@ -58,21 +80,82 @@ impl<'a> Commit<'a> {
         let timestamp = i.next().ok_or(CommitError)?;
         let committer_timestamp = timestamp.parse().map_err(|_| CommitError)?;
         // Strip off the "committer " from the front.
-        let committer = &i.next().ok_or(CommitError)?[11..];
+        let committer = &i.next().ok_or(CommitError)?[10..];
+
+        // Whatever is left of the header after the committer line is extra headers like
+        // `gpgsig`, `encoding`, or `mergetag`, folded continuation lines and all.
+        let extra_headers = parse_extra_headers(rest);
 
         let commit = Commit {
             preamble: preamble,
             author: author,
             author_timestamp: author_timestamp,
-            author_timezone: author_tz,
+            author_timezone: author_tz.to_string(),
             committer: committer,
             committer_timestamp: committer_timestamp,
-            committer_timezone: committer_tz,
-            message: message,
+            committer_timezone: committer_tz.to_string(),
+            extra_headers: extra_headers,
+            message: message.to_string(),
         };
 
         Ok(commit)
     }
+
+    /// Whether this commit carries a `gpgsig` header. Rewriting the author/committer timestamps
+    /// of a signed commit invalidates its signature, so callers should check this before
+    /// amending and either strip the signature with `without_signature` or refuse.
+    pub fn has_signature(&self) -> bool {
+        self.extra_headers.iter().any(|h| h.name == "gpgsig")
+    }
+
+    /// Return a copy of this commit with its `gpgsig` header (if any) removed. All other extra
+    /// headers are kept, in order.
+    pub fn without_signature(&self) -> Commit<'a> {
+        let mut commit = self.clone();
+        commit.extra_headers.retain(|h| h.name != "gpgsig");
+        commit
+    }
+}
+
+/// Split `s` at its first newline, returning the line (without the newline) and everything after
+/// it. If there's no newline, the whole string is the line and the remainder is empty.
+fn split_line(s: &str) -> (&str, &str) {
+    match s.find('\n') {
+        Some(idx) => (&s[..idx], &s[idx + 1..]),
+        None => (s, ""),
+    }
+}
+
+/// Parse the header lines that appear after `committer` into a list of `ExtraHeader`s. `s` is
+/// expected to either be empty or end with a newline, as the tail of a commit header does.
+fn parse_extra_headers(s: &str) -> Vec<ExtraHeader> {
+    let mut headers = Vec::new();
+    let bytes = s.as_bytes();
+    let mut pos = 0;
+
+    while pos < s.len() {
+        let start = pos;
+
+        // Consume the header's name line.
+        pos = match s[pos..].find('\n') {
+            Some(idx) => pos + idx + 1,
+            None => s.len(),
+        };
+
+        // Consume any folded continuation lines, which git indents with a single leading space.
+        while pos < s.len() && bytes[pos] == b' ' {
+            pos = match s[pos..].find('\n') {
+                Some(idx) => pos + idx + 1,
+                None => s.len(),
+            };
+        }
+
+        let raw = &s[start..pos];
+        let name = raw.splitn(2, ' ').next().unwrap_or("");
+        headers.push(ExtraHeader { name: name, raw: raw });
+    }
+
+    headers
 }
 
 /// Test that parsing actually works!
@@ -104,6 +187,7 @@ parent 30b08f0d64ab1b436713cbd43d6cd43dc0d967e3
         assert_eq!(commit.committer, "Bryan Burgers <bryan@burgers.io>");
         assert_eq!(commit.committer_timestamp, 1524753225);
         assert_eq!(commit.committer_timezone, "-0500");
+        assert!(commit.extra_headers.is_empty());
         assert_eq!(commit.message, "Test commit\n");
     }
 
@@ -159,4 +243,41 @@ Initial commit
         assert_eq!(commit.committer_timezone, "-0500");
         assert_eq!(commit.message, "Initial commit\n");
     }
+
+    #[test]
+    fn parse_gpgsig_commit() {
+        // concat! joins separate string literals, so the leading space on each folded
+        // continuation line survives -- unlike a `\`-continued literal, which would eat it as
+        // line-leading whitespace along with the newline.
+        let commit = concat!(
+            "tree cb44699325a0f4d127979cc8ae82354dd7e80ac6\n",
+            "author Bryan Burgers <bryan@burgers.io> 1524752605 -0500\n",
+            "committer Bryan Burgers <bryan@burgers.io> 1524753225 -0500\n",
+            "gpgsig -----BEGIN PGP SIGNATURE-----\n",
+            " \n",
+            " iQEzBAABCAAdFiEE\n",
+            " -----END PGP SIGNATURE-----\n",
+            "encoding ISO-8859-1\n",
+            "\n",
+            "Signed commit\n"
+        );
+
+        let parsed = Commit::parse(commit).unwrap();
+
+        assert!(parsed.has_signature());
+        assert_eq!(parsed.extra_headers.len(), 2);
+        assert_eq!(parsed.extra_headers[0].name, "gpgsig");
+        assert_eq!(
+            parsed.extra_headers[0].raw,
+            "gpgsig -----BEGIN PGP SIGNATURE-----\n \n iQEzBAABCAAdFiEE\n -----END PGP SIGNATURE-----\n"
+        );
+        assert_eq!(parsed.extra_headers[1].name, "encoding");
+        assert_eq!(parsed.extra_headers[1].raw, "encoding ISO-8859-1\n");
+        assert_eq!(parsed.message, "Signed commit\n");
+
+        let stripped = parsed.without_signature();
+        assert!(!stripped.has_signature());
+        assert_eq!(stripped.extra_headers.len(), 1);
+        assert_eq!(stripped.extra_headers[0].name, "encoding");
+    }
 }
@@ -1,17 +1,23 @@
 extern crate clap;
+extern crate git2;
 extern crate rayon;
 extern crate sha1;
+extern crate sha2;
 
 mod commit;
+mod digest;
 mod search;
 
 use clap::{App, Arg};
 use commit::Commit;
+use digest::{HashAlgorithm, ObjectHasher, UnknownHashAlgorithm};
+use git2::Repository;
 use rayon::prelude::*;
 use search::Search;
 use std::fmt::Write;
 use std::iter::Iterator;
-use std::process::{exit, Command};
+use std::process::exit;
+use std::str;
 
 fn main() {
     let result = run();
@@ -30,71 +36,231 @@ fn run() -> Result<(), ApplicationError> {
         .about("Force a commit hash to have a given prefix")
         .arg(
             Arg::with_name("prefix")
-                .help("The hexidecimal prefix to calculate")
+                .help("The hexidecimal prefix to calculate. '?' or '_' can be used as a wildcard nibble, e.g. '07?1f?'")
                 .required(true)
                 .validator(|hex| {
-                    Search::parse(&hex).map_err(|search::SearchError { ch, pos }| {
-                        format!("In '{}', the character '{}' at position {} is not a hexidecimal character.", hex, ch as char, pos + 1)
+                    Search::parse(&hex).map_err(|search::SearchError { octet, pos }| {
+                        format!("In '{}', \"{}\" at position {} cannot be parsed as an octet.", hex, octet, pos + 1)
                     })?;
                     Ok(())
                 }),
         )
+        .arg(
+            Arg::with_name("drop-signature")
+                .long("drop-signature")
+                .help("Strip a commit's gpgsig header instead of refusing to amend it (rewriting timestamps invalidates the signature either way)"),
+        )
+        .arg(
+            Arg::with_name("object-format")
+                .long("object-format")
+                .help("Override the repo's object hash algorithm instead of reading extensions.objectFormat")
+                .takes_value(true)
+                .possible_values(&["sha1", "sha256"]),
+        )
+        .arg(
+            Arg::with_name("vary-timezone")
+                .long("vary-timezone")
+                .conflicts_with("vary-nonce")
+                .help("Also search over UTC offsets (applied to both author and committer) instead of only the original one"),
+        )
+        .arg(
+            Arg::with_name("vary-nonce")
+                .long("vary-nonce")
+                .help("Search by appending a trailing nonce to the message instead of touching any timestamp; much faster on commits with large messages"),
+        )
         .get_matches();
 
     // Both of these unwraps are safe because the argument processor already validated that prefix
     // exists and can be successfully parsed by Search::parse.
     let search = Search::parse(matches.value_of("prefix").unwrap()).unwrap();
 
-    // Get HEAD's commit blob
-    let output = Command::new("git")
-        .args(&["cat-file", "commit", "HEAD"])
-        .output()
-        .map_err(|_| ApplicationError::GitCatFileFailed)?;
-    let output = String::from_utf8(output.stdout).map_err(|_| ApplicationError::CommitNotUTF8)?;
+    // Open the repo and read HEAD's commit object straight out of the object database, so we get
+    // the exact bytes git would hash instead of shelling out to `git cat-file`.
+    let repo = Repository::open(".").map_err(ApplicationError::RepoOpenFailed)?;
+    let head = repo.head().map_err(ApplicationError::HeadNotFound)?;
+    let head_commit = head.peel_to_commit().map_err(ApplicationError::HeadNotFound)?;
+
+    // Figure out whether this repo hashes objects with SHA-1 or SHA-256, so we search against the
+    // hash the repo will actually use. A `--object-format` flag overrides the repo's own config,
+    // for repos we haven't converted yet or for testing.
+    let object_format = match matches.value_of("object-format") {
+        Some(format) => Some(format.to_string()),
+        None => repo
+            .config()
+            .ok()
+            .and_then(|config| config.get_string("extensions.objectFormat").ok()),
+    };
+    let algorithm = HashAlgorithm::from_config(object_format.as_deref())
+        .map_err(ApplicationError::UnknownHashAlgorithm)?;
+
+    // A prefix longer than the repo's own digest can never match anything; fail fast instead of
+    // searching forever.
+    if search.byte_len() > algorithm.width() {
+        return Err(ApplicationError::PrefixTooLong);
+    }
+
+    let odb = repo.odb().map_err(ApplicationError::CommitReadFailed)?;
+    let object = odb
+        .read(head_commit.id())
+        .map_err(ApplicationError::CommitReadFailed)?;
+    let text = str::from_utf8(object.data()).map_err(|_| ApplicationError::CommitParseFailed)?;
 
     // And parse it into something we can use
-    let commit = Commit::parse(&output).map_err(|_| ApplicationError::CommitParseFailed)?;
+    let commit = Commit::parse(text).map_err(|_| ApplicationError::CommitParseFailed)?;
+
+    // Rewriting the timestamps invalidates any gpgsig, so either the caller has told us to strip
+    // it or we refuse rather than silently producing a commit with a broken signature.
+    let commit = if commit.has_signature() {
+        if matches.is_present("drop-signature") {
+            commit.without_signature()
+        } else {
+            return Err(ApplicationError::SignedCommit);
+        }
+    } else {
+        commit
+    };
 
     // Calculate a NEW commit that matches the prefix that we want. This runs forever until it
     // succeeds.
-    let new_commit = force_prefix(&commit, &search);
-
-    // We've found a new commit that will make this commit match the prefix! Because we only mess
-    // with committer_timestamp and author_timestamp, we need to amend the current commit with
-    // these new values.
-    println!(
-        "GIT_COMMITTER_DATE=\"{} {}\" git commit --date=\"{} {}\" --amend --no-edit",
-        new_commit.committer_timestamp,
-        new_commit.committer_timezone,
-        new_commit.author_timestamp,
-        new_commit.author_timezone
-    );
+    let new_commit = if matches.is_present("vary-nonce") {
+        force_prefix_nonce(&commit, &search, algorithm)
+    } else {
+        let vary_timezone = matches.is_present("vary-timezone");
+        force_prefix(&commit, &search, algorithm, vary_timezone)
+    };
+
+    // We've found timestamps (and maybe a UTC offset, or a nonce) that make this commit match the
+    // prefix! Serialize the updated commit back into the exact bytes we hashed while searching,
+    // and write that object directly, instead of printing a command for the user to run by hand.
+    // We can't use git2's high-level `Repository::commit`, which only knows how to emit
+    // tree/parent/author/committer/message: any commit with extra headers (`gpgsig`, `encoding`,
+    // `mergetag`) would come out of it missing those headers, so its hash would have nothing to
+    // do with the one we just searched for.
+    let raw = serialize_commit(&new_commit);
+
+    // Write the object first without moving any ref, so we can double check that what we wrote
+    // really does match the search before HEAD starts pointing at it.
+    let new_oid = odb
+        .write(git2::ObjectType::Commit, raw.as_bytes())
+        .map_err(ApplicationError::CommitWriteFailed)?;
+
+    if !search.test(new_oid.as_bytes()) {
+        return Err(ApplicationError::CommitWriteFailed(git2::Error::from_str(
+            "the commit we wrote does not match the search we computed",
+        )));
+    }
+
+    // Now that we know the hash is right, point the current branch at the new commit. This is
+    // the same atomic step `git commit --amend` would take.
+    let ref_name = head.name().ok_or(ApplicationError::CommitParseFailed)?;
+    repo.reference(
+        ref_name,
+        new_oid,
+        true,
+        "git-force-prefix: amend with vanity hash",
+    )
+    .map_err(ApplicationError::UpdateRefFailed)?;
+
+    eprintln!("Amended HEAD to {}", new_oid);
 
     Ok(())
 }
 
-/// Find a new commit blob, based on the given one, that has a commit hash that matches the search.
-fn force_prefix(commit: &Commit, search: &Search) -> Commit {
-    // First, pre-create as much of the SHA1 hash and the constituent parts as possible.
+/// Join a commit's extra headers (`gpgsig`, `encoding`, `mergetag`, folded continuation lines and
+/// all) back into the exact text that sits between the committer line and the blank line before
+/// the message. Shared by `serialize_commit` and the search functions below so the bytes we search
+/// against and the bytes we eventually write can never drift apart.
+fn join_extra_headers(commit: &Commit) -> String {
+    commit.extra_headers.iter().map(|h| h.raw).collect()
+}
+
+/// Serialize a (possibly updated) `Commit` back into the exact bytes git hashes for its object:
+/// the unchanged preamble, the author/committer lines rebuilt from the current timestamp/timezone
+/// fields, any extra headers verbatim, the blank line, and the message.
+fn serialize_commit(commit: &Commit) -> String {
+    format!(
+        "{}author {} {} {}\ncommitter {} {} {}\n{}\n{}",
+        commit.preamble,
+        commit.author,
+        commit.author_timestamp,
+        commit.author_timezone,
+        commit.committer,
+        commit.committer_timestamp,
+        commit.committer_timezone,
+        join_extra_headers(commit),
+        commit.message,
+    )
+}
+
+/// Format signed minutes east of UTC back into the canonical git `±HHMM` offset string.
+fn format_offset_minutes(offset: i32) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let magnitude = offset.abs();
+    format!("{}{:02}{:02}", sign, magnitude / 60, magnitude % 60)
+}
+
+/// The set of UTC offsets, in minutes, that `--vary-timezone` searches over: every 15-minute
+/// step from `-12:00` to `+14:00`, the full range of offsets git itself will accept.
+fn timezone_candidates() -> Vec<i32> {
+    let mut offsets = Vec::new();
+    let mut minutes = -12 * 60;
+    while minutes <= 14 * 60 {
+        offsets.push(minutes);
+        minutes += 15;
+    }
+    offsets
+}
+
+/// Find a new commit blob, based on the given one, that has a commit hash that matches the
+/// search under the given hash algorithm. When `vary_timezone` is set, the author/committer UTC
+/// offset (the same candidate offset for both) is searched alongside the timestamps; otherwise
+/// the original offsets are kept so the output stays sensible.
+fn force_prefix(commit: &Commit, search: &Search, algorithm: HashAlgorithm, vary_timezone: bool) -> Commit {
+    // First, pre-create as much of the hash and the constituent parts as possible. The UTC
+    // offsets are pulled out into their own candidates below, so `b` and `c` now start right
+    // after where a timezone would go rather than containing one.
     let a = format!("{}author {} ", commit.preamble, commit.author);
     let a = a.as_bytes();
-    let b = format!(
-        " {}\ncommitter {} ",
-        commit.author_timezone, commit.committer
-    );
+    let b = format!("\ncommitter {} ", commit.committer);
     let b = b.as_bytes();
-    let c = format!(" {}\n\n{}", commit.committer_timezone, commit.message);
+    // Any gpgsig/encoding/mergetag headers sit between the committer line and the blank line
+    // that starts the message; reassemble them byte-for-byte so signed/encoded commits round-trip.
+    let c = format!("\n{}\n{}", join_extra_headers(commit), commit.message);
     let c = c.as_bytes();
 
+    // Each candidate is a (author offset, committer offset) pair. Without --vary-timezone that's
+    // just the original commit's offsets (which may legitimately differ); with it, we instead try
+    // every 15-minute offset in git's valid range, applied to both fields at once so the extra
+    // dimension stays "for free" instead of squaring the search space. The original pair is only
+    // among those candidates when it is one shared offset on that 15-minute grid.
+    let tz_candidates: Vec<(String, String)> = if vary_timezone {
+        timezone_candidates()
+            .into_iter()
+            .map(|offset| {
+                let formatted = format_offset_minutes(offset);
+                (formatted.clone(), formatted)
+            })
+            .collect()
+    } else {
+        vec![(
+            commit.author_timezone.clone(),
+            commit.committer_timezone.clone(),
+        )]
+    };
+
     let mut iter = 0..;
     let mut found = false;
 
     let mut author_timestamp = 0;
     let mut committer_timestamp = 0;
+    let mut author_timezone = tz_candidates[0].0.clone();
+    let mut committer_timezone = tz_candidates[0].1.clone();
 
-    let len = a.len() + 10 + b.len() + 10 + c.len();
+    // Every offset candidate is exactly 5 bytes (`±HHMM`), so the length doesn't vary by which
+    // one we pick, only the (assumed 10-digit) timestamps do, as before.
+    let len = a.len() + 10 + 1 + 5 + b.len() + 10 + 1 + 5 + c.len();
 
-    let mut m = sha1::Sha1::new();
+    let mut m = ObjectHasher::new(algorithm);
     m.update(b"commit ");
     m.update(len.to_string().as_bytes());
     m.update(b"\0");
@@ -105,14 +271,26 @@ fn force_prefix(commit: &Commit, search: &Search) -> Commit {
         let i = iter.next().unwrap();
 
         // Search (in parallel) as many commits as possible, where the author timestamp is between
-        // the original commit's author timestamp and the new committer timestamp
-        let parallel_iterator = (0..(i + 1)).into_par_iter();
-        let result = parallel_iterator.find_any(|j| {
+        // the original commit's author timestamp and the new committer timestamp, crossed with
+        // every timezone candidate.
+        let width = tz_candidates.len() as i64;
+        let parallel_iterator = (0..(i + 1) * width).into_par_iter();
+        let result = parallel_iterator.find_any(|idx| {
+            let j = idx / width;
+            let tz_idx = (idx % width) as usize;
             let author_timestamp = commit.author_timestamp + j;
             let committer_timestamp = commit.author_timestamp + i;
+            let (author_tz, committer_tz) = &tz_candidates[tz_idx];
             // If we used these timestamps, what would the commit hash be?
-            let h =
-                calculate_hash_predigest(m.clone(), author_timestamp, b, committer_timestamp, c);
+            let h = calculate_hash_predigest(
+                m.clone(),
+                author_timestamp,
+                author_tz,
+                b,
+                committer_timestamp,
+                committer_tz,
+                c,
+            );
             // Does that commit hash match?
             let f = search.test(&h);
             if f {
@@ -126,41 +304,107 @@ fn force_prefix(commit: &Commit, search: &Search) -> Commit {
             f
         });
 
-        if let Some(j) = result {
+        if let Some(idx) = result {
             found = true;
+            let j = idx / width;
+            let tz_idx = (idx % width) as usize;
             author_timestamp = commit.author_timestamp + j;
             committer_timestamp = commit.author_timestamp + i;
+            author_timezone = tz_candidates[tz_idx].0.clone();
+            committer_timezone = tz_candidates[tz_idx].1.clone();
         }
-
-        // Old, single-core code
-        /*
-        for j in 0..(i+1) {
-            attempts += 1;
-
-            author_timestamp = commit.author_timestamp + j;
-            committer_timestamp = commit.author_timestamp + i;
-            let h = calculate_hash(a, author_timestamp, b, committer_timestamp, c);
-            if search.test(&h) {
-                let mut s = String::new();
-                for &byte in h.iter() {
-                    write!(&mut s, "{:02x}", byte).expect("Unable to write");
-                }
-                eprintln!("Found {} after {} attempts", s, attempts);
-                found = true;
-                break
-            }
-        }
-        */
     }
 
-    // New commit is exactly the same as the old, except with its timestamps changed.
+    // New commit is exactly the same as the old, except with its timestamps (and maybe UTC
+    // offsets) changed.
     let mut new_commit = commit.clone();
     new_commit.author_timestamp = author_timestamp;
     new_commit.committer_timestamp = committer_timestamp;
+    new_commit.author_timezone = author_timezone;
+    new_commit.committer_timezone = committer_timezone;
 
     new_commit
 }
 
+/// How many hex digits of nonce `force_prefix_nonce` appends to the message. Fixed-width so the
+/// object length (and thus the git header we hash before the content) doesn't depend on which
+/// candidate we're trying.
+const NONCE_HEX_WIDTH: usize = 16;
+
+/// Trailer line `force_prefix_nonce` appends after the message, in the usual `Key: value` git
+/// trailer style, followed immediately by the nonce.
+const NONCE_TRAILER: &str = "\nforce-prefix-nonce: ";
+
+/// How many nonces to search per batch. Large enough to keep all cores busy between checks of
+/// `find_map_any`'s result.
+const NONCE_BATCH_SIZE: u64 = 1 << 20;
+
+/// Find a new commit, based on the given one, whose hash matches the search by appending a
+/// trailing hex nonce to the message instead of touching any timestamp.
+///
+/// In `force_prefix`, every attempt re-hashes everything after the author name, message included,
+/// so the bigger the message, the more that costs. By keeping the author/committer lines exactly
+/// as they are and only changing a fixed-width nonce right at the end, everything before the
+/// nonce (the trailer included) is identical across attempts: we hash it into `m` exactly once,
+/// and each attempt only has to clone that state and feed it the 16-digit candidate nonce before
+/// finalizing.
+fn force_prefix_nonce(commit: &Commit, search: &Search, algorithm: HashAlgorithm) -> Commit {
+    let fixed = format!(
+        "{}author {} {} {}\ncommitter {} {} {}\n{}\n{}",
+        commit.preamble,
+        commit.author,
+        commit.author_timestamp,
+        commit.author_timezone,
+        commit.committer,
+        commit.committer_timestamp,
+        commit.committer_timezone,
+        join_extra_headers(commit),
+        commit.message,
+    );
+    let fixed = fixed.as_bytes();
+
+    let len = fixed.len() + NONCE_TRAILER.len() + NONCE_HEX_WIDTH;
+
+    let mut m = ObjectHasher::new(algorithm);
+    m.update(b"commit ");
+    m.update(len.to_string().as_bytes());
+    m.update(b"\0");
+    m.update(fixed);
+    m.update(NONCE_TRAILER.as_bytes());
+
+    let mut base = 0u64;
+    let nonce = loop {
+        let found = (base..base.wrapping_add(NONCE_BATCH_SIZE))
+            .into_par_iter()
+            .find_map_any(|candidate| {
+                let nonce = format!("{:0width$x}", candidate, width = NONCE_HEX_WIDTH);
+                let mut attempt = m.clone();
+                attempt.update(nonce.as_bytes());
+                let h = attempt.finalize();
+                if search.test(&h) {
+                    let mut s = String::new();
+                    for &byte in h.iter() {
+                        write!(&mut s, "{:02x}", byte).expect("Unable to write");
+                    }
+                    eprintln!("Found {}", s);
+                    Some(nonce)
+                } else {
+                    None
+                }
+            });
+
+        if let Some(nonce) = found {
+            break nonce;
+        }
+
+        base = base.wrapping_add(NONCE_BATCH_SIZE);
+    };
+
+    let mut new_commit = commit.clone();
+    new_commit.message = format!("{}{}{}", commit.message, NONCE_TRAILER, nonce);
+    new_commit
+}
+
 // Code that goes slightly slower because it has to hash a little bit more
 /*
 #[inline]
@@ -189,51 +433,97 @@ fn calculate_hash(a: &[u8], author_timestamp: i64, b: &[u8], committer_timestamp
 
 #[inline]
 fn calculate_hash_predigest(
-    mut m: sha1::Sha1,
+    mut m: ObjectHasher,
     author_timestamp: i64,
+    author_timezone: &str,
     b: &[u8],
     committer_timestamp: i64,
+    committer_timezone: &str,
     c: &[u8],
-) -> [u8; 20] {
+) -> Vec<u8> {
     let author_timestamp = author_timestamp.to_string();
     let author_timestamp = author_timestamp.as_bytes();
     let committer_timestamp = committer_timestamp.to_string();
     let committer_timestamp = committer_timestamp.as_bytes();
 
     m.update(author_timestamp);
+    m.update(b" ");
+    m.update(author_timezone.as_bytes());
     m.update(b);
     m.update(committer_timestamp);
+    m.update(b" ");
+    m.update(committer_timezone.as_bytes());
     m.update(c);
 
-    let digest = m.digest();
-    digest.bytes()
+    m.finalize()
 }
 
 /// List of potential errors that we can run into
 enum ApplicationError {
-    /// We couldn't get the current commit blob
-    GitCatFileFailed,
-    /// The commit wasn't UTF-8 (WHO DOES THIS!?)
-    CommitNotUTF8,
+    /// We couldn't open the repo in the current directory
+    RepoOpenFailed(git2::Error),
+    /// We couldn't find or read HEAD
+    HeadNotFound(git2::Error),
+    /// We couldn't read the HEAD commit out of the object database
+    CommitReadFailed(git2::Error),
     /// We couldn't parse the commit blob
     CommitParseFailed,
+    /// `extensions.objectFormat` or `--object-format` named an algorithm we don't know
+    UnknownHashAlgorithm(UnknownHashAlgorithm),
+    /// The requested prefix is longer than the repo's own digest, so it can never match
+    PrefixTooLong,
+    /// HEAD is GPG-signed and we weren't told to drop the now-invalid signature
+    SignedCommit,
+    /// We couldn't write the rewritten commit object
+    CommitWriteFailed(git2::Error),
+    /// We couldn't point the branch at the rewritten commit
+    UpdateRefFailed(git2::Error),
 }
 
 impl ApplicationError {
     fn output_and_exit_code(&self) -> i32 {
         match *self {
-            ApplicationError::GitCatFileFailed => {
-                eprintln!("ERROR: Failed to call git. Is the current directory a repo?");
+            ApplicationError::RepoOpenFailed(ref e) => {
+                eprintln!("ERROR: Failed to open the repo in the current directory: {}", e);
                 1
             }
-            ApplicationError::CommitNotUTF8 => {
-                eprintln!("ERROR: The commit could not be parsed as UTF-8");
+            ApplicationError::HeadNotFound(ref e) => {
+                eprintln!("ERROR: Failed to read HEAD: {}", e);
+                1
+            }
+            ApplicationError::CommitReadFailed(ref e) => {
+                eprintln!("ERROR: Failed to read the HEAD commit: {}", e);
                 1
             }
             ApplicationError::CommitParseFailed => {
                 eprintln!("ERROR: Failed to parse the commit");
                 1
             }
+            ApplicationError::UnknownHashAlgorithm(UnknownHashAlgorithm(ref v)) => {
+                eprintln!(
+                    "ERROR: '{}' is not a known object hash algorithm (expected sha1 or sha256)",
+                    v
+                );
+                1
+            }
+            ApplicationError::PrefixTooLong => {
+                eprintln!("ERROR: The requested prefix is longer than the repo's object hash, so it can never match");
+                1
+            }
+            ApplicationError::SignedCommit => {
+                eprintln!(
+                    "ERROR: HEAD is GPG-signed. Rewriting its timestamps would invalidate the signature; pass --drop-signature to strip it and continue."
+                );
+                1
+            }
+            ApplicationError::CommitWriteFailed(ref e) => {
+                eprintln!("ERROR: Failed to write the rewritten commit: {}", e);
+                1
+            }
+            ApplicationError::UpdateRefFailed(ref e) => {
+                eprintln!("ERROR: Failed to update the branch ref: {}", e);
+                1
+            }
         }
     }
 }
@@ -1,59 +1,83 @@
 /// The representation of the prefix we're looking for
 ///
 /// This largely represents a string like `07b1f4`, but is broken apart to optimize checking
-/// against a byte slice that represents a hash.
+/// against a byte slice that represents a hash. It also supports `?`/`_` wildcard nibbles, e.g.
+/// `07?1f?`, so a search isn't limited to a contiguous leading prefix.
 #[derive(Debug)]
 pub struct Search {
-    /// The number of bytes in `bytes`
+    /// The number of bytes in `bytes` (and `masks`)
     compare_len: usize,
-    /// Full bytes that need to be compared. E.g., if our string is 07b1f4, this is vec![0x07,
-    /// 0xb1, 0xf4]
+    /// The expected value of each byte that needs to be compared, already masked so it can be
+    /// compared directly against `val[i] & masks[i]`. E.g., if our string is `07b1f4`, this is
+    /// vec![0x07, 0xb1, 0xf4]; if it's `0?b1f4`, this is vec![0x00, 0xb1, 0xf4] (the wildcard
+    /// nibble's bits are zeroed since `masks` will ignore them anyway).
     bytes: Vec<u8>,
-    /// If the given string had an odd number of characters, e.g. `07b1f4a`, this represents the
-    /// nibble Some(0x0a) (because it is not a full byte and does not go in bytes).
-    odd: Option<u8>,
+    /// The AND-mask to apply to a candidate byte before comparing it to `bytes`. `0xff` means
+    /// both nibbles are compared, `0xf0`/`0x0f` means only the high/low nibble is, and `0x00`
+    /// means the whole byte is a wildcard.
+    masks: Vec<u8>,
+    /// If the given string had an odd number of characters, e.g. `07b1f4a`, this is the extra
+    /// nibble: its value (or `0` if it's a wildcard) and whether it's a wildcard.
+    odd: Option<(u8, bool)>,
 }
 
 /// Failure to parse a search string.
 #[derive(Debug)]
 pub struct SearchError {
-    /// The unexpected character.
-    pub ch: u8,
-    /// The position in the string.
+    /// The one- or two-character octet that couldn't be parsed, e.g. `"g7"`. Reporting the whole
+    /// octet (rather than just the one bad character) means the error points at the actual
+    /// problem even when only one of the two characters is invalid.
+    pub octet: String,
+    /// The position in the string where `octet` starts.
     pub pos: usize,
 }
 
+/// Parse a single hex nibble. `Some(Some(value))` for a hex digit, `Some(None)` for a wildcard
+/// (`?` or `_`), `None` if it's neither.
+fn parse_nibble(ch: u8) -> Option<Option<u8>> {
+    match ch {
+        b'A'...b'F' => Some(Some(ch - b'A' + 10)),
+        b'a'...b'f' => Some(Some(ch - b'a' + 10)),
+        b'0'...b'9' => Some(Some(ch - b'0')),
+        b'?' | b'_' => Some(None),
+        _ => None,
+    }
+}
+
 impl Search {
     /// Parse a string into a search object
     pub fn parse(s: &str) -> Result<Search, SearchError> {
         let mut i = 0;
         let bytes = s.as_bytes();
-        let mut vec = Vec::new();
-        while i < s.len() - 1 {
-            let b1 = match bytes[i] {
-                b'A'...b'F' => bytes[i] - b'A' + 10,
-                b'a'...b'f' => bytes[i] - b'a' + 10,
-                b'0'...b'9' => bytes[i] - b'0',
+        let mut values = Vec::new();
+        let mut masks = Vec::new();
+
+        while i + 1 < s.len() {
+            let n0 = parse_nibble(bytes[i]);
+            let n1 = parse_nibble(bytes[i + 1]);
+
+            let (n0, n1) = match (n0, n1) {
+                (Some(n0), Some(n1)) => (n0, n1),
                 _ => {
                     return Err(SearchError {
-                        ch: bytes[i],
+                        octet: s[i..i + 2].to_string(),
                         pos: i,
                     });
                 }
             };
-            let b2 = match bytes[i + 1] {
-                b'A'...b'F' => bytes[i + 1] - b'A' + 10,
-                b'a'...b'f' => bytes[i + 1] - b'a' + 10,
-                b'0'...b'9' => bytes[i + 1] - b'0',
-                _ => {
-                    return Err(SearchError {
-                        ch: bytes[i + 1],
-                        pos: i + 1,
-                    });
-                }
-            };
-            let v = b1 << 4 | b2;
-            vec.push(v);
+
+            let mut value = 0u8;
+            let mut mask = 0u8;
+            if let Some(v) = n0 {
+                value |= v << 4;
+                mask |= 0xf0;
+            }
+            if let Some(v) = n1 {
+                value |= v;
+                mask |= 0x0f;
+            }
+            values.push(value);
+            masks.push(mask);
 
             i += 2;
         }
@@ -61,15 +85,14 @@ impl Search {
         let odd = match s.len() % 2 {
             0 => None,
             1 => {
-                let b = bytes[s.len() - 1];
-                match b {
-                    b'A'...b'F' => Some(b - b'A' + 10),
-                    b'a'...b'f' => Some(b - b'a' + 10),
-                    b'0'...b'9' => Some(b - b'0'),
-                    _ => {
+                let pos = s.len() - 1;
+                match parse_nibble(bytes[pos]) {
+                    Some(Some(v)) => Some((v, false)),
+                    Some(None) => Some((0, true)),
+                    None => {
                         return Err(SearchError {
-                            ch: b,
-                            pos: s.len() - 1,
+                            octet: s[pos..].to_string(),
+                            pos: pos,
                         });
                     }
                 }
@@ -77,29 +100,32 @@ impl Search {
             _ => unreachable!(),
         };
 
-        // If an odd number of characters were specified, then we need to check the odd character in a
-        // special way.
         Ok(Search {
-            compare_len: vec.len(),
-            bytes: vec,
+            compare_len: values.len(),
+            bytes: values,
+            masks: masks,
             odd: odd,
         })
     }
 
+    /// The number of leading bytes of a digest that this search cares about, i.e. how long a
+    /// digest needs to be for `test` to have an opinion about all of it.
+    pub fn byte_len(&self) -> usize {
+        self.compare_len + if self.odd.is_some() { 1 } else { 0 }
+    }
+
     /// Test whether a slice of bytes matches the given search.
     #[inline]
     pub fn test(&self, val: &[u8]) -> bool {
-        // Is it a match on the whole bytes?
-        if &val[0..self.compare_len] == &self.bytes[..] {
-            // Yes! If all we have is whole bytes, we're good. If we still need to check the odd
-            // nibble, check that too.
-            match self.odd {
-                Some(b) => val[self.compare_len] >> 4 == b,
-                None => true,
+        for i in 0..self.compare_len {
+            if val[i] & self.masks[i] != self.bytes[i] {
+                return false;
             }
-        } else {
-            // Nope.
-            false
+        }
+
+        match self.odd {
+            Some((nibble, wildcard)) => wildcard || val[self.compare_len] >> 4 == nibble,
+            None => true,
         }
     }
 }
@@ -115,8 +141,8 @@ mod tests {
 
         assert!(s.is_err());
 
-        let SearchError { ch, pos } = s.unwrap_err();
-        assert_eq!(ch, b'z');
+        let SearchError { octet, pos } = s.unwrap_err();
+        assert_eq!(octet, "z0");
         assert_eq!(pos, 2);
     }
 
@@ -126,9 +152,9 @@ mod tests {
 
         assert!(s.is_err());
 
-        let SearchError { ch, pos } = s.unwrap_err();
-        assert_eq!(ch, b'z');
-        assert_eq!(pos, 3);
+        let SearchError { octet, pos } = s.unwrap_err();
+        assert_eq!(octet, "0z");
+        assert_eq!(pos, 2);
     }
 
     #[test]
@@ -137,11 +163,19 @@ mod tests {
 
         assert!(s.is_err());
 
-        let SearchError { ch, pos } = s.unwrap_err();
-        assert_eq!(ch, b'z');
+        let SearchError { octet, pos } = s.unwrap_err();
+        assert_eq!(octet, "z");
         assert_eq!(pos, 4);
     }
 
+    #[test]
+    fn test_parse_empty() {
+        let s = Search::parse("");
+
+        assert!(s.is_ok());
+        assert!(s.unwrap().test(&[0x00, 0x11, 0x22]));
+    }
+
     #[test]
     fn test_succeeded_parse() {
         let s = Search::parse("0123456789abcdefABCDEF");
@@ -176,4 +210,23 @@ mod tests {
 
         assert!(!s.test(&[0x01, 0x23, 0x55]));
     }
+
+    #[test]
+    fn test_wildcard_nibble() {
+        let s = Search::parse("07?1f?").unwrap();
+
+        assert!(s.test(&[0x07, 0x21, 0xf3]));
+        assert!(s.test(&[0x07, 0xa1, 0xff]));
+        assert!(!s.test(&[0x08, 0x21, 0xf3]));
+        assert!(!s.test(&[0x07, 0x22, 0xf3]));
+    }
+
+    #[test]
+    fn test_wildcard_odd_nibble() {
+        let s = Search::parse("dead?").unwrap();
+
+        assert!(s.test(&[0xde, 0xad, 0x00]));
+        assert!(s.test(&[0xde, 0xad, 0xf0]));
+        assert!(!s.test(&[0xde, 0xae, 0x00]));
+    }
 }